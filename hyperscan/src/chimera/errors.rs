@@ -1,3 +1,4 @@
+use std::backtrace::BacktraceStatus;
 use std::ffi::CStr;
 use std::fmt;
 use std::result::Result as StdResult;
@@ -7,16 +8,63 @@ use thiserror::Error;
 
 use crate::ffi::chimera as ffi;
 
+/// A captured `std::backtrace::Backtrace`, boxed so that `Error` stays cheap to
+/// move when backtrace capture is disabled (the common case, since it is gated
+/// behind `RUST_BACKTRACE`/`RUST_LIB_BACKTRACE`).
+///
+/// This wraps the standard library type purely so `Error` can keep deriving
+/// `PartialEq`; a backtrace is never part of an error's identity, so equality
+/// ignores it entirely.
+pub struct Backtrace(std::backtrace::Backtrace);
+
+impl fmt::Debug for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
+impl PartialEq for Backtrace {
+    fn eq(&self, _other: &Self) -> bool {
+        true
+    }
+}
+
+fn capture_backtrace() -> Option<Box<Backtrace>> {
+    // `Backtrace::capture` caches the environment lookup for the whole process,
+    // so the variables are read again on every call.
+    let enabled = match std::env::var("RUST_LIB_BACKTRACE") {
+        Ok(s) => s != "0",
+        Err(_) => std::env::var("RUST_BACKTRACE").map_or(false, |s| s != "0"),
+    };
+
+    if enabled {
+        Some(Box::new(Backtrace(std::backtrace::Backtrace::force_capture())))
+    } else {
+        None
+    }
+}
+
 /// A type for errors returned by Chimera functions.
+///
+/// Every variant carries a `backtrace` field: the backtrace captured at the
+/// point the FFI call returned this error, if `RUST_BACKTRACE`/
+/// `RUST_LIB_BACKTRACE` was enabled. Use [`Error::backtrace`] to read it
+/// regardless of variant.
 #[derive(Debug, Error, PartialEq)]
 pub enum Error {
     /// A parameter passed to this function was invalid.
     #[error("A parameter passed to this function was invalid.")]
-    Invalid,
+    Invalid { backtrace: Option<Box<Backtrace>> },
 
     /// A memory allocation failed.
     #[error("A memory allocation failed.")]
-    NoMem,
+    NoMem { backtrace: Option<Box<Backtrace>> },
 
     /// The engine was terminated by callback.
     ///
@@ -24,38 +72,38 @@ pub enum Error {
     /// but that the callback function requested that scanning cease after a match
     /// was located.
     #[error("The engine was terminated by callback.")]
-    ScanTerminated,
+    ScanTerminated { backtrace: Option<Box<Backtrace>> },
 
     /// The pattern compiler failed, and the `ch_compile_error_t` should be inspected for more detail.
     #[error("The pattern compiler failed with more detail, {0}.")]
-    CompileError(CompileError),
+    CompileError(#[source] CompileError, Option<Box<Backtrace>>),
 
     /// The pattern compiler failed.
     #[error("he pattern compiler failed.")]
-    CompilerError,
+    CompilerError { backtrace: Option<Box<Backtrace>> },
 
     /// The given database was built for a different version of the Chimera matcher.
     #[error("he pattern compiler failed.")]
-    DbVersionError,
+    DbVersionError { backtrace: Option<Box<Backtrace>> },
 
     /// The given database was built for a different platform (i.e., CPU type).
     #[error("The given database was built for a different platform (i.e., CPU type).")]
-    DbPlatformError,
+    DbPlatformError { backtrace: Option<Box<Backtrace>> },
 
     /// The given database was built for a different mode of operation.
     ///
     /// This error is returned when streaming calls are used with a non-streaming database and vice versa.
     #[error("The given database was built for a different mode of operation.")]
-    DbModeError,
+    DbModeError { backtrace: Option<Box<Backtrace>> },
 
     /// A parameter passed to this function was not correctly aligned.
     #[error("A parameter passed to this function was not correctly aligned.")]
-    BadAlign,
+    BadAlign { backtrace: Option<Box<Backtrace>> },
 
     /// The memory allocator did not correctly return memory suitably aligned for
     /// the largest representable data type on this platform.
     #[error("The memory allocator did not correctly return memory suitably aligned.")]
-    BadAlloc,
+    BadAlloc { backtrace: Option<Box<Backtrace>> },
 
     /// The scratch region was already in use.
     ///
@@ -73,38 +121,74 @@ pub enum Error {
     /// Note: Not all concurrent uses of scratch regions may be detected. This error
     /// is intended as a best-effort debugging tool, not a guarantee.
     #[error("The scratch region was already in use.")]
-    ScratchInUse,
+    ScratchInUse { backtrace: Option<Box<Backtrace>> },
 
     /// Returned when pcre_exec (called for some expressions internally from `ch_scan`) failed due to a fatal error.
     #[error("Failed due to a fatal error")]
-    FailInternal,
+    FailInternal { backtrace: Option<Box<Backtrace>> },
 
     /// Unknown error code
     #[error("Unknown error code: {0}")]
-    Code(ffi::ch_error_t),
+    Code(ffi::ch_error_t, Option<Box<Backtrace>>),
+}
+
+impl Error {
+    /// The backtrace captured at the point this error was produced, if `RUST_BACKTRACE`
+    /// or `RUST_LIB_BACKTRACE` was enabled at the time.
+    ///
+    /// `Error` does not implement `std::error::Error::provide`, so this accessor
+    /// is the only way to retrieve it.
+    pub fn backtrace(&self) -> Option<&std::backtrace::Backtrace> {
+        let backtrace = match self {
+            Error::Invalid { backtrace }
+            | Error::NoMem { backtrace }
+            | Error::ScanTerminated { backtrace }
+            | Error::CompilerError { backtrace }
+            | Error::DbVersionError { backtrace }
+            | Error::DbPlatformError { backtrace }
+            | Error::DbModeError { backtrace }
+            | Error::BadAlign { backtrace }
+            | Error::BadAlloc { backtrace }
+            | Error::ScratchInUse { backtrace }
+            | Error::FailInternal { backtrace }
+            | Error::CompileError(_, backtrace)
+            | Error::Code(_, backtrace) => backtrace,
+        };
+
+        backtrace.as_deref().map(|backtrace| &backtrace.0)
+    }
 }
 
 impl From<ffi::ch_error_t> for Error {
     fn from(err: ffi::ch_error_t) -> Self {
         use Error::*;
 
+        let backtrace = capture_backtrace();
+
         match err {
-            ffi::CH_INVALID => Invalid,
-            ffi::CH_NOMEM => NoMem,
-            ffi::CH_SCAN_TERMINATED => ScanTerminated,
+            ffi::CH_INVALID => Invalid { backtrace },
+            ffi::CH_NOMEM => NoMem { backtrace },
+            ffi::CH_SCAN_TERMINATED => ScanTerminated { backtrace },
             // ffi::CH_COMPILER_ERROR => HsError::CompileError,
-            ffi::CH_DB_VERSION_ERROR => DbVersionError,
-            ffi::CH_DB_PLATFORM_ERROR => DbPlatformError,
-            ffi::CH_DB_MODE_ERROR => DbModeError,
-            ffi::CH_BAD_ALIGN => BadAlign,
-            ffi::CH_BAD_ALLOC => BadAlloc,
-            ffi::CH_SCRATCH_IN_USE => ScratchInUse,
-            ffi::CH_FAIL_INTERNAL => FailInternal,
-            _ => Code(err),
+            ffi::CH_DB_VERSION_ERROR => DbVersionError { backtrace },
+            ffi::CH_DB_PLATFORM_ERROR => DbPlatformError { backtrace },
+            ffi::CH_DB_MODE_ERROR => DbModeError { backtrace },
+            ffi::CH_BAD_ALIGN => BadAlign { backtrace },
+            ffi::CH_BAD_ALLOC => BadAlloc { backtrace },
+            ffi::CH_SCRATCH_IN_USE => ScratchInUse { backtrace },
+            ffi::CH_FAIL_INTERNAL => FailInternal { backtrace },
+            _ => Code(err, backtrace),
         }
     }
 }
 
+/// `Self::Error` used to be erased to `anyhow::Error` here; it is now the
+/// concrete [`Error`] enum so callers can `match` on variants like
+/// `Error::ScratchInUse` instead of downcasting. `?`-propagation into an
+/// `anyhow::Result` still compiles unchanged via the blanket
+/// `From<E: std::error::Error> for anyhow::Error`; only call sites that use
+/// `anyhow`-specific methods (`.context()`, `downcast_ref::<Error>()` on the
+/// previously-erased type) need updating to work with `Error` directly.
 pub trait AsResult
 where
     Self: Sized,
@@ -118,7 +202,10 @@ where
         self.ok().map(op)
     }
 
-    fn and_then<U, F: FnOnce(Self::Output) -> StdResult<U, Self::Error>>(self, op: F) -> StdResult<U, Self::Error> {
+    fn and_then<U, F: FnOnce(Self::Output) -> StdResult<U, Self::Error>>(
+        self,
+        op: F,
+    ) -> StdResult<U, Self::Error> {
         self.ok().and_then(op)
     }
 
@@ -129,13 +216,13 @@ where
 
 impl AsResult for ffi::ch_error_t {
     type Output = ();
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn ok(self) -> StdResult<Self::Output, Self::Error> {
         if self == ffi::CH_SUCCESS as ffi::ch_error_t {
             Ok(())
         } else {
-            Err(Error::from(self).into())
+            Err(Error::from(self))
         }
     }
 }
@@ -168,6 +255,8 @@ impl fmt::Debug for CompileError {
     }
 }
 
+impl std::error::Error for CompileError {}
+
 impl PartialEq for CompileError {
     fn eq(&self, other: &Self) -> bool {
         self.as_ptr() == other.as_ptr()
@@ -196,6 +285,8 @@ impl CompileError {
     }
 }
 
+/// See the note on [`AsResult`]: `Self::Error` is the concrete [`Error`]
+/// rather than an erased `anyhow::Error`.
 pub trait AsCompileResult {
     type Output;
     type Error: fmt::Display;
@@ -205,15 +296,91 @@ pub trait AsCompileResult {
 
 impl AsCompileResult for ffi::ch_error_t {
     type Output = ();
-    type Error = anyhow::Error;
+    type Error = Error;
 
     fn ok_or(self, err: *mut ffi::ch_compile_error_t) -> Result<Self::Output, Self::Error> {
         if self == ffi::CH_SUCCESS as ffi::ch_error_t {
             Ok(())
         } else if self == ffi::CH_COMPILER_ERROR && !err.is_null() {
-            Err(Error::CompileError(unsafe { CompileError::from_ptr(err) }).into())
+            Err(Error::CompileError(
+                unsafe { CompileError::from_ptr(err) },
+                capture_backtrace(),
+            ))
         } else {
-            Err(Error::from(self).into())
+            Err(Error::from(self))
         }
     }
 }
+
+/// The outcome of a scan that completed without a genuine error.
+///
+/// Nothing in this module constructs `ScanOutcome`: `From<ffi::ch_error_t>`
+/// maps `CH_SCAN_TERMINATED` to `Error::ScanTerminated` whether or not the
+/// termination was requested by the caller's own `MatchEventCallback`
+/// returning `Matching::Terminate`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanOutcome {
+    /// The target buffer was scanned in its entirety.
+    Completed,
+
+    /// Scanning stopped early because the caller's `MatchEventCallback` returned
+    /// `Matching::Terminate`.
+    Terminated,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as StdError;
+    use std::ffi::CString;
+    use std::os::raw::{c_char, c_int};
+
+    use super::*;
+
+    #[test]
+    fn backtrace_is_none_without_rust_backtrace() {
+        std::env::remove_var("RUST_BACKTRACE");
+        std::env::remove_var("RUST_LIB_BACKTRACE");
+
+        assert!(capture_backtrace().is_none());
+    }
+
+    #[test]
+    fn backtrace_is_captured_when_rust_backtrace_is_enabled() {
+        std::env::set_var("RUST_BACKTRACE", "1");
+        let captured = capture_backtrace();
+        std::env::remove_var("RUST_BACKTRACE");
+
+        assert!(captured.is_some());
+    }
+
+    #[test]
+    fn same_variant_errors_are_equal_regardless_of_backtrace() {
+        std::env::set_var("RUST_BACKTRACE", "1");
+        let with_backtrace = Error::ScratchInUse {
+            backtrace: capture_backtrace(),
+        };
+        std::env::remove_var("RUST_BACKTRACE");
+        let without_backtrace = Error::ScratchInUse { backtrace: None };
+
+        assert_eq!(with_backtrace, without_backtrace);
+    }
+
+    #[test]
+    fn compile_error_is_the_source_of_its_wrapping_error() {
+        let message = CString::new("bad pattern").unwrap();
+        let mut raw = ffi::ch_compile_error_t {
+            message: message.as_ptr() as *mut c_char,
+            expression: -1 as c_int,
+        };
+
+        let compile_error = unsafe { CompileError::from_ptr(&mut raw as *mut _) };
+        let err = Error::CompileError(compile_error, None);
+
+        let source = err.source().expect("CompileError should be the source");
+        assert_eq!(source.to_string(), "bad pattern");
+
+        // `raw` lives on the stack, not memory Chimera itself allocated, so
+        // skip running `CompileError`'s drop (`ch_free_compile_error`).
+        std::mem::forget(err);
+    }
+}